@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// Tunables that used to be baked into `main()` and `state.rs`, now
+/// loadable from a `config.toml` (or any other source the `config`
+/// crate understands) so operators can run multiple instances without
+/// recompiling.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub bind_addr: String,
+    pub cleanup_interval_secs: u64,
+    pub inactivity_threshold_secs: u64,
+    pub watcher_stack_size: usize,
+    pub mailbox_capacity: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: String::from("127.0.0.1:7878"),
+            cleanup_interval_secs: 30,
+            inactivity_threshold_secs: 60 * 15,
+            watcher_stack_size: 1024 * 75,
+            mailbox_capacity: 256,
+        }
+    }
+}
+
+impl Config {
+    /// Loads `config.toml` from the working directory, falling back to
+    /// `Config::default()` for anything it doesn't set.
+    pub fn load() -> Self {
+        let mut settings = config::Config::new();
+        if let Err(e) = settings.merge(config::File::with_name("config").required(false)) {
+            eprintln!("unable to read config.toml, using defaults: {}", e);
+            return Config::default();
+        }
+        match settings.try_into() {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("unable to parse config.toml, using defaults: {}", e);
+                Config::default()
+            }
+        }
+    }
+}
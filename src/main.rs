@@ -13,9 +13,14 @@ use jemallocator;
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 
+mod config;
 mod state;
+mod store;
 mod router;
 
+use config::Config;
+use state::GlobalState;
+
 /// Request counting struct, used to track the number of requests made.
 ///
 /// Due to being shared across many worker threads, the internal counter
@@ -40,7 +45,23 @@ mod router;
 /// Start a server and call the `Handler` we've defined above
 /// for each `Request` we receive.
 pub fn main() {
-    let addr = "127.0.0.1:7878";
-    println!("Listening for requests at http://{}", addr);
-    gotham::start(addr, router::ignite())
+    if std::env::args().any(|arg| arg == "--print-default") {
+        let defaults = toml::to_string_pretty(&Config::default())
+            .expect("unable to serialize default config");
+        print!("{}", defaults);
+        return;
+    }
+
+    let config = Config::load();
+    let global = GlobalState::new(&config);
+
+    let shutdown_handle = global.clone();
+    ctrlc::set_handler(move || {
+        println!("shutting down");
+        shutdown_handle.shutdown();
+        std::process::exit(0);
+    }).expect("unable to install signal handler");
+
+    println!("Listening for requests at http://{}", config.bind_addr);
+    gotham::start(config.bind_addr.clone(), router::ignite(global))
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use cookie::SameSite;
+use futures::future::Loop;
 use futures::{future, Future, Stream};
 use futures::prelude::*;
 use gotham::handler::{HandlerFuture, IntoHandlerError};
@@ -11,14 +13,21 @@ use gotham::pipeline::single::single_pipeline;
 use gotham::router::builder::*;
 use gotham::router::Router;
 use gotham::state::{FromState, State};
+use gotham_derive::StateData;
 use hyper::{Body, Response, StatusCode};
 use mime::Mime;
 use rayon::prelude::*;
 use serde::*;
 use serde_json;
 use simd_json;
+use tokio::timer::Delay;
 
-use crate::state::GlobalState;
+use crate::state::{DeliveryStatus, GlobalState, Message, RecipientKey};
+
+/// How long a `/poll` request waits for a message before returning empty.
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a pending `/poll` re-checks the mailbox while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 fn get_list(state: State) -> (State, Response<Body>) {
     let message = {
@@ -54,6 +63,7 @@ fn pub_key(state: State) -> (State, Response<Body>) {
 struct HandshakeReq {
     name: String,
     key: String,
+    passphrase: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -77,7 +87,7 @@ fn handshake(mut state: State) -> Box<HandlerFuture> {
 
                 match body_content {
                     Ok(req) => {
-                        let nonce = global.add_user(&req.name, &req.key);
+                        let nonce = global.add_user(&req.name, &req.key, req.passphrase.as_ref());
                         let result = match nonce {
                             Ok(nonce) =>
                                 HandshakeResult {
@@ -116,12 +126,139 @@ fn handshake(mut state: State) -> Box<HandlerFuture> {
 }
 
 
-pub fn ignite() -> Router {
-    // create the global to share across handlers
-    let users: GlobalState = GlobalState::new();
+#[derive(Serialize, Deserialize, Debug)]
+struct SendReq {
+    from: String,
+    to: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize)]
+struct SendResult {
+    success: bool,
+    err: String,
+    nonce: Option<String>,
+}
+
+fn send(mut state: State) -> Box<HandlerFuture> {
+    let global = GlobalState::borrow_from(&state).clone();
+    let f = Body::take_from(&mut state)
+        .concat2()
+        .then(move |full_body| match full_body {
+            Ok(valid_body) => {
+                let body_content = String::from_utf8(valid_body.to_vec())
+                    .map_err(|x| x.to_string())
+                    .and_then(|mut x|
+                        simd_json::serde::from_str::<SendReq>(x.as_mut_str())
+                            .map_err(|x| x.to_string()));
+
+                let result = match body_content {
+                    Ok(req) => match global.send(&req.from, &req.to, &req.ciphertext) {
+                        Ok(nonce) => SendResult { success: true, err: String::new(), nonce },
+                        Err(err) => SendResult { success: false, err, nonce: None },
+                    },
+                    Err(err) => SendResult { success: false, err, nonce: None },
+                };
+                let json = serde_json::to_string(&result).unwrap();
+                let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, json);
+                future::ok((state, res))
+            }
+            Err(e) => future::err((state, e.into_handler_error())),
+        });
+
+    Box::new(f)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BroadcastReq {
+    from: String,
+    /// Ciphertext proving `from`'s identity, authenticated the same way
+    /// `/send` authenticates its sender: it must decode under `from`'s
+    /// registered key and current nonce.
+    proof: String,
+    body: String,
+    body_nonce: String,
+    recipients: Vec<RecipientKey>,
+}
+
+#[derive(Serialize)]
+struct BroadcastResult {
+    success: bool,
+    err: String,
+    nonce: Option<String>,
+    delivered: Vec<DeliveryStatus>,
+}
+
+/// Fans a hybrid-encrypted group message out to every recipient's
+/// mailbox. The server only ever routes the sealed per-recipient key
+/// copies; it never recovers the message body.
+fn broadcast(mut state: State) -> Box<HandlerFuture> {
+    let global = GlobalState::borrow_from(&state).clone();
+    let f = Body::take_from(&mut state)
+        .concat2()
+        .then(move |full_body| match full_body {
+            Ok(valid_body) => {
+                let body_content = String::from_utf8(valid_body.to_vec())
+                    .map_err(|x| x.to_string())
+                    .and_then(|mut x|
+                        simd_json::serde::from_str::<BroadcastReq>(x.as_mut_str())
+                            .map_err(|x| x.to_string()));
+
+                let result = match body_content {
+                    Ok(req) => match global.broadcast(&req.from, &req.proof, &req.body, &req.body_nonce, &req.recipients) {
+                        Ok((nonce, delivered)) => BroadcastResult { success: true, err: String::new(), nonce, delivered },
+                        Err(err) => BroadcastResult { success: false, err, nonce: None, delivered: Vec::new() },
+                    },
+                    Err(err) => BroadcastResult { success: false, err, nonce: None, delivered: Vec::new() },
+                };
+                let json = serde_json::to_string(&result).unwrap();
+                let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, json);
+                future::ok((state, res))
+            }
+            Err(e) => future::err((state, e.into_handler_error())),
+        });
+
+    Box::new(f)
+}
+
+#[derive(Deserialize, StateData, gotham_derive::StaticResponseExtender)]
+struct PollQuery {
+    name: String,
+}
+
+/// Long-polls a user's mailbox, returning as soon as a message arrives
+/// or after `POLL_TIMEOUT` elapses with an empty list.
+fn poll(state: State) -> Box<HandlerFuture> {
+    let global = GlobalState::borrow_from(&state).clone();
+    let name = PollQuery::borrow_from(&state).name.clone();
+    let deadline = Instant::now() + POLL_TIMEOUT;
+
+    let f = future::loop_fn(global.mailbox(&name), move |mailbox| {
+        match &mailbox {
+            None => future::Either::A(future::ok(Loop::Break(Vec::new()))),
+            Some(rx) => match rx.try_recv() {
+                Ok(message) => future::Either::A(future::ok(Loop::Break(vec![message]))),
+                Err(_) if Instant::now() >= deadline => future::Either::A(future::ok(Loop::Break(Vec::new()))),
+                Err(_) => future::Either::B(
+                    Delay::new(Instant::now() + POLL_INTERVAL)
+                        .map_err(|e| panic!("timer failure: {}", e))
+                        .map(move |_| Loop::Continue(mailbox)),
+                ),
+            },
+        }
+    }).then(move |result: Result<Vec<Message>, ()>| {
+        let messages = result.unwrap_or_default();
+        let json = serde_json::to_string(&messages).unwrap();
+        let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, json);
+        future::ok((state, res))
+    });
+
+    Box::new(f)
+}
 
+pub fn ignite(global: GlobalState) -> Router {
     // create our state middleware to share the global
-    let middleware = StateMiddleware::new(users);
+    let middleware = StateMiddleware::new(global);
 
     // create a middleware pipeline from our middleware
     let pipeline = single_middleware(middleware);
@@ -133,6 +270,9 @@ pub fn ignite() -> Router {
     build_router(chain, pipelines, |route| {
         route.get("/list").to(get_list);
         route.get("/public-key").to(pub_key);
-        route.post("/handshake").to(handshake)
+        route.post("/handshake").to(handshake);
+        route.post("/send").to(send);
+        route.post("/broadcast").to(broadcast);
+        route.get("/poll").with_query_string_extractor::<PollQuery>().to(poll)
     })
 }
\ No newline at end of file
@@ -1,25 +1,71 @@
 use std::cell::RefCell;
 use std::ops::Add;
-use std::sync::{Arc, Mutex, RwLock};
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread::{JoinHandle, Thread};
 use std::time::{Duration, SystemTime};
 
-use hashbrown::hash_map::rayon::*;
-use hashbrown::HashMap;
-use rayon::prelude::*;
+use async_channel::{bounded, Receiver, Sender};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 use serde::*;
 use sodiumoxide::crypto::box_::*;
 use tokio::prelude::*;
 
+use crate::config::Config;
+use crate::store::{PersistentStore, StoredUser};
+
 type DecodePair = (Option<String>, Option<String>);
 
+const STORE_PATH: &str = "data.lmdb";
+
+/// A sealed copy of a group message's body key, addressed to one
+/// recipient with `box_::seal` under that recipient's registered key.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecipientKey {
+    pub name: String,
+    pub sealed_key: String,
+    pub key_nonce: String,
+}
+
+/// Whether a single recipient's mailbox actually accepted a broadcast
+/// envelope, so a partial failure doesn't read as a blanket one and a
+/// retry doesn't have to guess who already received it.
+#[derive(Serialize, Clone)]
+pub struct DeliveryStatus {
+    pub name: String,
+    pub delivered: bool,
+    pub err: Option<String>,
+}
+
+/// A message waiting in a user's mailbox.
+///
+/// `Direct` carries the plaintext the server recovered from `decode`.
+/// `Broadcast` carries a hybrid-encrypted group message: the server
+/// never sees its plaintext, only the `secretbox`-sealed body and the
+/// recipient's own sealed copy of the body key.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Message {
+    Direct {
+        from: String,
+        body: String,
+    },
+    Broadcast {
+        from: String,
+        body: String,
+        body_nonce: String,
+        sealed_key: String,
+        key_nonce: String,
+    },
+}
+
 struct UserRecord {
     time: RwLock<SystemTime>,
     pubkey: Arc<PublicKey>,
     nonce: RwLock<Nonce>,
     name: Arc<String>,
+    mailbox_tx: Sender<Message>,
+    mailbox_rx: Receiver<Message>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -49,10 +95,14 @@ impl Eq for UserRecord {}
 
 #[derive(Clone, StateData)]
 pub struct GlobalState {
-    data: Arc<RwLock<HashMap<String, Arc<UserRecord>>>>,
-    thread_flag: Arc<AtomicBool>,
+    data: Arc<DashMap<String, Arc<UserRecord>>>,
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    watcher: Arc<Mutex<Option<JoinHandle<()>>>>,
     public_key: Arc<PublicKey>,
     secret_key: Arc<SecretKey>,
+    store: Arc<PersistentStore>,
+    inactivity_threshold: Duration,
+    mailbox_capacity: usize,
 }
 
 impl UserRecord {
@@ -65,12 +115,6 @@ impl UserRecord {
     }
 }
 
-impl Drop for GlobalState {
-    fn drop(&mut self) {
-        self.thread_flag.store(true, SeqCst);
-    }
-}
-
 impl GlobalState {
     pub fn public_key(&self) -> &PublicKey {
         self.public_key.as_ref()
@@ -81,95 +125,206 @@ impl GlobalState {
     }
 
 
-    pub fn new() -> Self {
-        let keys = gen_keypair();
+    pub fn new(config: &Config) -> Self {
+        let store = Arc::new(PersistentStore::open(STORE_PATH));
+        let keys = store.load_or_init_keypair();
+        let mailbox_capacity = config.mailbox_capacity;
+        let data = store
+            .load_users()
+            .into_iter()
+            .filter_map(|stored| {
+                let pubkey = base64::decode(&stored.pubkey).ok()?;
+                let pubkey = PublicKey::from_slice(pubkey.as_slice())?;
+                let (mailbox_tx, mailbox_rx) = bounded(mailbox_capacity);
+                let record = UserRecord {
+                    time: RwLock::new(stored.last_active),
+                    pubkey: Arc::new(pubkey),
+                    nonce: RwLock::new(gen_nonce()),
+                    name: Arc::new(stored.name.clone()),
+                    mailbox_tx,
+                    mailbox_rx,
+                };
+                Some((stored.name, Arc::new(record)))
+            })
+            .collect();
         let res = GlobalState {
-            data: Arc::new(RwLock::new(HashMap::new())),
-            thread_flag: Arc::new(AtomicBool::new(false)),
+            data: Arc::new(data),
+            shutdown: Arc::new((Mutex::new(false), Condvar::new())),
+            watcher: Arc::new(Mutex::new(None)),
             public_key: Arc::new(keys.0),
             secret_key: Arc::new(keys.1),
+            store,
+            inactivity_threshold: Duration::from_secs(config.inactivity_threshold_secs),
+            mailbox_capacity,
         };
         {
             let ptr = res.clone();
-            let flag = res.thread_flag.clone();
+            let shutdown = res.shutdown.clone();
+            let cleanup_interval = Duration::from_secs(config.cleanup_interval_secs);
             let thd = std::thread::Builder::new()
                 .name(String::from("global watcher"))
-                .stack_size(1024 * 75)
+                .stack_size(config.watcher_stack_size)
                 .spawn(move || {
-                    loop {
-                        if flag.load(Relaxed) {
-                            break;
-                        } else {
-                            println!("cleaning up");
-                            ptr.clean_up();
-                            std::thread::sleep(Duration::from_secs(30));
-                        }
+                    let (lock, cvar) = &*shutdown;
+                    let mut done = lock.lock().expect("shutdown lock poisoned");
+                    while !*done {
+                        println!("cleaning up");
+                        ptr.clean_up();
+                        let (guard, _) = cvar
+                            .wait_timeout(done, cleanup_interval)
+                            .expect("shutdown condvar wait failed");
+                        done = guard;
                     }
                 }).expect("unable to spawn watching thread");
+            *res.watcher.lock().expect("watcher lock poisoned") = Some(thd);
         }
         res
     }
 
-    pub fn update(&self, name: &String) -> Option<String> {
-        let mut reader = self.data.write().expect("unable to read data");
-        if let Some(user) = reader.get_mut(name) {
-            Some(user.update())
-        } else {
-            None
+    /// Wakes the watcher thread, joins it, and flushes the persistent
+    /// store. Meant to be called once, from the process's signal
+    /// handler, before the server itself stops.
+    pub fn shutdown(&self) {
+        {
+            let (lock, cvar) = &*self.shutdown;
+            let mut done = lock.lock().expect("shutdown lock poisoned");
+            *done = true;
+            cvar.notify_all();
         }
+        if let Some(handle) = self.watcher.lock().expect("watcher lock poisoned").take() {
+            let _ = handle.join();
+        }
+        if let Err(e) = self.store.flush() {
+            eprintln!("unable to flush store on shutdown: {}", e);
+        }
+    }
+
+    /// Rotates `name`'s nonce and bumps its last-active time in memory
+    /// only. This runs on every successful `decode` — i.e. on every
+    /// `/send` and `/broadcast` — so it must
+    /// stay off the LMDB write path: only the durable bits (pubkey,
+    /// claim) go through `store.put_user`/`put_claim`, at registration
+    /// time, not the ephemeral nonce or last-active timestamp.
+    pub fn update(&self, name: &String) -> Option<String> {
+        self.data.get(name).map(|user| user.update())
     }
 
     pub fn check(&self, name: &String) -> bool {
-        let reader = self.data.read().unwrap();
-        reader.contains_key(name)
+        self.data.contains_key(name)
     }
 
     pub fn get_list(&self) -> Vec<UserJson> {
-        self.data.read().unwrap().par_values().map(|x| UserJson::from(x.as_ref())).collect()
+        self.data.iter().map(|x| UserJson::from(x.value().as_ref())).collect()
     }
 
+    /// Sweeps for inactive users without ever taking a lock over the
+    /// whole map: a read-only pass over the shards finds candidates,
+    /// then each one is evicted with a non-blocking `try_entry`. An
+    /// entry that's locked elsewhere (e.g. mid-handshake) is simply
+    /// left for the next sweep instead of stalling this one.
     pub fn clean_up(&self) {
-        let mut writer = self.data.write().unwrap();
-        let threshold = Duration::from_secs(60 * 15);
         let now = SystemTime::now();
-        let todo: Vec<Arc<String>> = writer
-            .par_values()
-            .filter(|x| {
-                x.time.read().unwrap().add(threshold) < now
-            })
-            .map(|x| x.name.clone())
+        let todo: Vec<String> = self
+            .data
+            .iter()
+            .filter(|x| x.value().time.read().unwrap().add(self.inactivity_threshold) < now)
+            .map(|x| x.key().clone())
             .collect();
-        for i in todo {
-            writer.remove(i.as_ref());
+        for name in todo {
+            let evicted = match self.data.try_entry(name.clone()) {
+                Some(Entry::Occupied(entry)) => {
+                    let still_expired =
+                        entry.get().time.read().unwrap().add(self.inactivity_threshold) < now;
+                    if still_expired {
+                        entry.remove();
+                        true
+                    } else {
+                        false
+                    }
+                }
+                _ => false,
+            };
+            if evicted {
+                if let Err(e) = self.store.remove_user(&name) {
+                    eprintln!("unable to remove evicted user {}: {}", name, e);
+                }
+            }
         }
     }
 
-    pub fn add_user(&self, name: &String, key: &String) -> Result<String, String> {
-        base64::decode(&key).map_err(|x| x.to_string()).and_then(|x| {
-            PublicKey::from_slice(x.as_slice()).ok_or("cannot convert key".to_string())
-        }).and_then(|x| {
-            if self.check(name) {
-                Err("name exsits".to_string())
-            } else {
+    /// Registers a name, optionally protecting it with a passphrase.
+    ///
+    /// If the name was previously claimed (even if its session has since
+    /// been evicted by `clean_up`), a matching passphrase must be
+    /// presented or registration is refused. Only the Argon2 hash of the
+    /// passphrase is ever persisted.
+    ///
+    /// The whole check → claim-hash → persist → insert sequence runs
+    /// under a single `DashMap::entry` for `name`, so two concurrent
+    /// handshakes for the same unclaimed name can't both observe it as
+    /// free and race to register it: the second one blocks on the entry
+    /// lock and then sees it already `Occupied`.
+    pub fn add_user(&self, name: &String, key: &String, passphrase: Option<&String>) -> Result<String, String> {
+        let pubkey = base64::decode(&key)
+            .map_err(|x| x.to_string())
+            .and_then(|x| PublicKey::from_slice(x.as_slice()).ok_or("cannot convert key".to_string()))?;
+
+        match self.data.entry(name.clone()) {
+            Entry::Occupied(_) => Err("name exsits".to_string()),
+            Entry::Vacant(entry) => {
+                let claim_hash = self.claim_hash(name, passphrase)?;
                 let nonce = gen_nonce();
                 let encoded = base64::encode(&nonce.0);
+                let (mailbox_tx, mailbox_rx) = bounded(self.mailbox_capacity);
                 let user = UserRecord {
                     time: RwLock::new(SystemTime::now()),
-                    pubkey: Arc::new(x),
+                    pubkey: Arc::new(pubkey),
                     nonce: RwLock::new(nonce),
                     name: Arc::new(name.clone()),
+                    mailbox_tx,
+                    mailbox_rx,
                 };
-                self.data.write().unwrap().insert(name.clone(), Arc::new(user));
+                self.store.put_user(&StoredUser {
+                    name: name.clone(),
+                    pubkey: key.clone(),
+                    last_active: user.time.read().expect("unable to read time").clone(),
+                })?;
+                if let Some(hash) = claim_hash {
+                    self.store.put_claim(name, &hash)?;
+                }
+                entry.insert(Arc::new(user));
                 Ok(encoded)
             }
-        })
+        }
+    }
+
+    /// Checks `name`'s existing claim (if any) against the supplied
+    /// passphrase, returning the Argon2 hash to persist for a fresh or
+    /// renewed claim, or `None` if the name isn't being claimed at all.
+    fn claim_hash(&self, name: &String, passphrase: Option<&String>) -> Result<Option<String>, String> {
+        match (self.store.get_claim(name), passphrase) {
+            (Some(existing), Some(passphrase)) => {
+                let matches = argon2::verify_encoded(&existing, passphrase.as_bytes())
+                    .map_err(|e| e.to_string())?;
+                if matches {
+                    Ok(Some(existing))
+                } else {
+                    Err("incorrect passphrase for claimed name".to_string())
+                }
+            }
+            (Some(_), None) => Err("name is claimed; passphrase required".to_string()),
+            (None, Some(passphrase)) => {
+                let salt = sodiumoxide::randombytes::randombytes(16);
+                let hash = argon2::hash_encoded(passphrase.as_bytes(), &salt, &argon2::Config::default())
+                    .map_err(|e| e.to_string())?;
+                Ok(Some(hash))
+            }
+            (None, None) => Ok(None),
+        }
     }
 
     pub fn decode(&self, name: &String, msg: &String) -> DecodePair {
-        let t = {
-            let reader = self.data.read().unwrap();
-            reader.get(name).cloned()
-        };
+        let t = self.data.get(name).map(|x| x.value().clone());
         let decoded = if let Some(user) = t {
             let text = open(msg.as_bytes(),
                             &user.nonce.read().unwrap(),
@@ -187,4 +342,84 @@ impl GlobalState {
         let nonce = if decoded.is_some() { self.update(name) } else { None };
         (decoded, nonce)
     }
+
+    /// Returns the mailbox a user can poll for incoming messages.
+    pub fn mailbox(&self, name: &String) -> Option<Receiver<Message>> {
+        self.data.get(name).map(|user| user.mailbox_rx.clone())
+    }
+
+    /// Verifies `from` via the existing nonce/`decode` flow and, if that
+    /// succeeds, enqueues the recovered plaintext into `to`'s mailbox.
+    ///
+    /// `decode` rotates `from`'s nonce on every successful call, so the
+    /// fresh nonce is returned here too — exactly like `HandshakeResult`
+    /// does — for the caller to use on its next message.
+    pub fn send(&self, from: &String, to: &String, ciphertext: &String) -> Result<Option<String>, String> {
+        let (decoded, nonce) = self.decode(from, ciphertext);
+        let body = decoded.ok_or_else(|| "unable to verify sender".to_string())?;
+        let recipient = self.data.get(to).ok_or_else(|| "recipient not found".to_string())?;
+        recipient
+            .mailbox_tx
+            .try_send(Message::Direct { from: from.clone(), body })
+            .map_err(|e| e.to_string())?;
+        Ok(nonce)
+    }
+
+    /// Fans the envelope out into each registered recipient's mailbox as
+    /// a `Message::Broadcast`; an unregistered recipient is reported as
+    /// undelivered with an "unknown recipient" error.
+    /// The server only ever handles the sealed per-recipient key copies;
+    /// the body itself stays `secretbox`-sealed end-to-end.
+    ///
+    /// `proof` is authenticated the same way `send` authenticates
+    /// `from`: it must decode under `from`'s registered key and current
+    /// nonce. Its plaintext is discarded — only a successful `decode`
+    /// matters — and, as with `send`, the freshly rotated nonce is
+    /// handed back for the caller's next message.
+    ///
+    /// Every recipient is attempted regardless of earlier failures; the
+    /// per-recipient outcomes are returned so a partial failure (e.g. one
+    /// full mailbox, or a recipient evicted mid-broadcast by `clean_up`)
+    /// doesn't read as a blanket one, and a retry can tell which
+    /// recipients still need the message.
+    pub fn broadcast(
+        &self,
+        from: &String,
+        proof: &String,
+        body: &String,
+        body_nonce: &String,
+        recipients: &[RecipientKey],
+    ) -> Result<(Option<String>, Vec<DeliveryStatus>), String> {
+        let (decoded, nonce) = self.decode(from, proof);
+        decoded.ok_or_else(|| "unable to verify sender".to_string())?;
+
+        let deliveries = recipients
+            .iter()
+            .map(|recipient| match self.data.get(&recipient.name) {
+                None => DeliveryStatus {
+                    name: recipient.name.clone(),
+                    delivered: false,
+                    err: Some("unknown recipient".to_string()),
+                },
+                Some(user) => {
+                    let sent = user.mailbox_tx.try_send(Message::Broadcast {
+                        from: from.clone(),
+                        body: body.clone(),
+                        body_nonce: body_nonce.clone(),
+                        sealed_key: recipient.sealed_key.clone(),
+                        key_nonce: recipient.key_nonce.clone(),
+                    });
+                    match sent {
+                        Ok(()) => DeliveryStatus { name: recipient.name.clone(), delivered: true, err: None },
+                        Err(e) => DeliveryStatus {
+                            name: recipient.name.clone(),
+                            delivered: false,
+                            err: Some(e.to_string()),
+                        },
+                    }
+                }
+            })
+            .collect();
+        Ok((nonce, deliveries))
+    }
 }
\ No newline at end of file
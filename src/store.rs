@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, Transaction, WriteFlags};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_::{gen_keypair, PublicKey, SecretKey};
+
+const KEYPAIR_DB: &str = "keypair";
+const USERS_DB: &str = "users";
+const CLAIMS_DB: &str = "claims";
+const PUBLIC_KEY_KEY: &[u8] = b"public_key";
+const SECRET_KEY_KEY: &[u8] = b"secret_key";
+
+/// A user record as it is written to / read from the users table.
+///
+/// Only the durable bits are persisted here; the per-session nonce is
+/// re-generated on rehydration since it only ever protects a single
+/// handshake.
+#[derive(Serialize, Deserialize)]
+pub struct StoredUser {
+    pub name: String,
+    pub pubkey: String,
+    pub last_active: SystemTime,
+}
+
+/// LMDB-backed persistence for the server's long-term keypair and its
+/// registered users, so a restart doesn't invalidate every session.
+pub struct PersistentStore {
+    env: Environment,
+    keypair_db: Database,
+    users_db: Database,
+    claims_db: Database,
+}
+
+impl PersistentStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+        std::fs::create_dir_all(&path).expect("unable to create lmdb directory");
+        let env = Environment::new()
+            .set_max_dbs(3)
+            .set_map_size(1024 * 1024 * 1024)
+            .open(path.as_ref())
+            .expect("unable to open lmdb environment");
+        let keypair_db = env
+            .create_db(Some(KEYPAIR_DB), DatabaseFlags::empty())
+            .expect("unable to open keypair db");
+        let users_db = env
+            .create_db(Some(USERS_DB), DatabaseFlags::empty())
+            .expect("unable to open users db");
+        let claims_db = env
+            .create_db(Some(CLAIMS_DB), DatabaseFlags::empty())
+            .expect("unable to open claims db");
+        PersistentStore {
+            env,
+            keypair_db,
+            users_db,
+            claims_db,
+        }
+    }
+
+    /// Loads the server's long-term keypair, generating and persisting a
+    /// fresh one the first time the store is opened.
+    pub fn load_or_init_keypair(&self) -> (PublicKey, SecretKey) {
+        let existing = {
+            let txn = self.env.begin_ro_txn().expect("unable to begin ro txn");
+            let keys = txn
+                .get(self.keypair_db, &PUBLIC_KEY_KEY)
+                .and_then(|pk| txn.get(self.keypair_db, &SECRET_KEY_KEY).map(|sk| (pk, sk)))
+                .ok()
+                .and_then(|(pk, sk)| PublicKey::from_slice(pk).zip(SecretKey::from_slice(sk)));
+            keys
+        };
+        if let Some(keys) = existing {
+            return keys;
+        }
+
+        let (public_key, secret_key) = gen_keypair();
+        let mut txn = self.env.begin_rw_txn().expect("unable to begin rw txn");
+        txn.put(self.keypair_db, &PUBLIC_KEY_KEY, &public_key.0, WriteFlags::empty())
+            .expect("unable to persist public key");
+        txn.put(self.keypair_db, &SECRET_KEY_KEY, &secret_key.0, WriteFlags::empty())
+            .expect("unable to persist secret key");
+        txn.commit().expect("unable to commit keypair");
+        (public_key, secret_key)
+    }
+
+    /// Loads every user that was registered in a previous run.
+    pub fn load_users(&self) -> Vec<StoredUser> {
+        let txn = self.env.begin_ro_txn().expect("unable to begin ro txn");
+        let mut cursor = txn
+            .open_ro_cursor(self.users_db)
+            .expect("unable to open users cursor");
+        cursor
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(_, value)| serde_json::from_slice::<StoredUser>(value).ok())
+            .collect()
+    }
+
+    pub fn put_user(&self, user: &StoredUser) -> Result<(), String> {
+        let value = serde_json::to_vec(user).map_err(|e| e.to_string())?;
+        let mut txn = self.env.begin_rw_txn().map_err(|e| e.to_string())?;
+        txn.put(self.users_db, &user.name, &value, WriteFlags::empty())
+            .map_err(|e| e.to_string())?;
+        txn.commit().map_err(|e| e.to_string())
+    }
+
+    /// Forces any outstanding writes out to disk. LMDB already syncs on
+    /// commit, but this gives shutdown an explicit point to call.
+    pub fn flush(&self) -> Result<(), String> {
+        self.env.sync(true).map_err(|e| e.to_string())
+    }
+
+    pub fn remove_user(&self, name: &str) -> Result<(), String> {
+        let mut txn = self.env.begin_rw_txn().map_err(|e| e.to_string())?;
+        match txn.del(self.users_db, &name, None) {
+            Ok(()) => {}
+            Err(lmdb::Error::NotFound) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        txn.commit().map_err(|e| e.to_string())
+    }
+
+    /// Looks up the Argon2 hash protecting a (possibly offline) name, if
+    /// the owner ever set a passphrase for it. Kept in its own table so
+    /// ownership survives `clean_up` evicting the name from `users`.
+    pub fn get_claim(&self, name: &str) -> Option<String> {
+        let txn = self.env.begin_ro_txn().expect("unable to begin ro txn");
+        txn.get(self.claims_db, &name)
+            .ok()
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    pub fn put_claim(&self, name: &str, encoded_hash: &str) -> Result<(), String> {
+        let mut txn = self.env.begin_rw_txn().map_err(|e| e.to_string())?;
+        txn.put(self.claims_db, &name, &encoded_hash, WriteFlags::empty())
+            .map_err(|e| e.to_string())?;
+        txn.commit().map_err(|e| e.to_string())
+    }
+}